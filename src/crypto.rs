@@ -25,17 +25,18 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use ring::aead;
-use ring::digest;
-use ring::hkdf;
-use ring::hmac;
-use ring::unauthenticated_stream;
-
 use crate::Result;
 use crate::Error;
 
 use crate::octets;
 
+mod ring_backend;
+mod secret;
+
+pub use ring_backend::RingCryptoBackend;
+pub use secret::is_equal;
+pub use secret::Secret;
+
 const INITIAL_SALT: [u8; 20] = [
     0xef, 0x4f, 0xb0, 0xab, 0xb4, 0x74, 0x70, 0xc4, 0x1b, 0xef,
     0xcf, 0x80, 0x31, 0x33, 0x4f, 0xae, 0x48, 0x5e, 0x09, 0xa0,
@@ -64,201 +65,403 @@ pub enum Algorithm {
     ChaCha20_Poly1305,
 }
 
-impl Algorithm {
-    fn get_ring_aead(self) -> &'static aead::Algorithm {
+/// The HMAC digest used to derive a given AEAD's secrets.
+///
+/// This is part of the neutral `Algorithm` surface rather than a detail of
+/// any particular backend, since every backend needs to agree on which
+/// digest a given cipher suite uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Digest {
+    Sha256,
+    Sha384,
+}
+
+impl Digest {
+    fn len(self) -> usize {
         match self {
-            Algorithm::AES128_GCM => &aead::AES_128_GCM,
-            Algorithm::AES256_GCM => &aead::AES_256_GCM,
-            Algorithm::ChaCha20_Poly1305 => &aead::CHACHA20_POLY1305,
-            Algorithm::Null => panic!("Not a valid AEAD"),
+            Digest::Sha256 => 32,
+            Digest::Sha384 => 48,
         }
     }
+}
 
-    fn get_ring_stream(self) -> &'static unauthenticated_stream::Algorithm {
+impl Algorithm {
+    pub fn digest(self) -> Digest {
         match self {
-            Algorithm::AES128_GCM => &unauthenticated_stream::AES_128_CTR,
-            Algorithm::AES256_GCM => &unauthenticated_stream::AES_256_CTR,
-            Algorithm::ChaCha20_Poly1305 => &unauthenticated_stream::CHACHA20,
+            Algorithm::AES128_GCM => Digest::Sha256,
+            Algorithm::AES256_GCM => Digest::Sha384,
+            Algorithm::ChaCha20_Poly1305 => Digest::Sha256,
             Algorithm::Null => panic!("Not a valid AEAD"),
         }
     }
 
-    fn get_ring_digest(self) -> &'static digest::Algorithm {
+    pub fn key_len(self) -> usize {
         match self {
-            Algorithm::AES128_GCM => &digest::SHA256,
-            Algorithm::AES256_GCM => &digest::SHA384,
-            Algorithm::ChaCha20_Poly1305 => &digest::SHA256,
+            Algorithm::AES128_GCM => 16,
+            Algorithm::AES256_GCM => 32,
+            Algorithm::ChaCha20_Poly1305 => 32,
             Algorithm::Null => panic!("Not a valid AEAD"),
         }
     }
 
-    pub fn key_len(self) -> usize {
-        self.get_ring_aead().key_len()
-    }
-
     pub fn tag_len(self) -> usize {
-        self.get_ring_aead().tag_len()
+        match self {
+            Algorithm::AES128_GCM => 16,
+            Algorithm::AES256_GCM => 16,
+            Algorithm::ChaCha20_Poly1305 => 16,
+            Algorithm::Null => panic!("Not a valid AEAD"),
+        }
     }
 
     pub fn nonce_len(self) -> usize {
-        self.get_ring_aead().nonce_len()
+        match self {
+            Algorithm::AES128_GCM => 12,
+            Algorithm::AES256_GCM => 12,
+            Algorithm::ChaCha20_Poly1305 => 12,
+            Algorithm::Null => panic!("Not a valid AEAD"),
+        }
     }
 
     pub fn pn_nonce_len(self) -> usize {
         // For pkt num decryption a 4 bytes explicit counter is used along
         // with the normal nonce for both ChaCha20 and AES-CTR.
-        self.get_ring_aead().nonce_len() + 4
+        self.nonce_len() + 4
     }
 }
 
-pub struct Open {
+/// A pluggable crypto provider.
+///
+/// `Open`/`Seal` and the key-derivation helpers in this module are generic
+/// over `CryptoBackend` so the AEAD seal/open, header-protection keystream
+/// and HKDF extract/expand can be swapped for an NSS or OpenSSL-backed
+/// implementation without touching the rest of the crate. `RingCryptoBackend`
+/// is the default, and the only one provided today.
+pub trait CryptoBackend: Sized {
+    /// Opaque AEAD decryption key.
+    type OpeningKey;
+
+    /// Opaque AEAD encryption key.
+    type SealingKey;
+
+    /// Opaque header-protection key used to remove the packet number mask.
+    type HpOpeningKey;
+
+    /// Opaque header-protection key used to apply the packet number mask.
+    type HpSealingKey;
+
+    /// Opaque HKDF pseudo-random key.
+    type Prk;
+
+    fn opening_key(alg: Algorithm, key: &[u8]) -> Result<Self::OpeningKey>;
+
+    fn sealing_key(alg: Algorithm, key: &[u8]) -> Result<Self::SealingKey>;
+
+    fn hp_opening_key(alg: Algorithm, key: &[u8]) -> Result<Self::HpOpeningKey>;
+
+    fn hp_sealing_key(alg: Algorithm, key: &[u8]) -> Result<Self::HpSealingKey>;
+
+    fn open(key: &Self::OpeningKey, nonce: &[u8], ad: &[u8], buf: &mut [u8])
+                                                            -> Result<usize>;
+
+    fn seal(key: &Self::SealingKey, nonce: &[u8], ad: &[u8], buf: &mut [u8],
+                                            tag_len: usize) -> Result<usize>;
+
+    fn open_keystream(key: &Self::HpOpeningKey, nonce: &[u8], buf: &mut [u8])
+                                                            -> Result<usize>;
+
+    fn seal_keystream(key: &Self::HpSealingKey, nonce: &[u8], buf: &mut [u8])
+                                                            -> Result<usize>;
+
+    /// HKDF-Extract as per RFC 5869, using `digest` as the hash function.
+    fn extract(digest: Digest, salt: &[u8], ikm: &[u8]) -> Self::Prk;
+
+    /// Wraps already-extracted key material (e.g. a TLS traffic secret) as
+    /// a PRK, so it can be fed back into `expand` without re-extracting it.
+    fn prk_from_bytes(digest: Digest, bytes: &[u8]) -> Self::Prk;
+
+    /// HKDF-Expand as per RFC 5869.
+    fn expand(prk: &Self::Prk, info: &[u8], out: &mut [u8]);
+}
+
+/// The longest AEAD nonce used by any `Algorithm` this crate supports.
+pub const MAX_NONCE_LEN: usize = 12;
+
+/// A generous upper bound on the QUIC packet header used as AEAD associated
+/// data, so `seal_batch`/`open_batch` can build it into a stack buffer
+/// rather than allocating one per packet.
+const MAX_AD_LEN: usize = 256;
+
+pub struct Open<B: CryptoBackend = RingCryptoBackend> {
     alg: Algorithm,
-    pn_key: unauthenticated_stream::DecryptingKey,
-    key: aead::OpeningKey,
-    nonce: Vec<u8>,
+    pn_key: B::HpOpeningKey,
+    key: B::OpeningKey,
+    // Raw copies of `key`/`pn_key` above, kept solely so the key material is
+    // wiped when `Open` is dropped: `pn_key`/`key` are opaque backend
+    // objects we have no way to force to zero their own internal state.
+    #[allow(dead_code)]
+    key_secret: Secret,
+    #[allow(dead_code)]
+    pn_key_secret: Secret,
+    nonce: [u8; MAX_NONCE_LEN],
+    nonce_len: usize,
 }
 
-impl Open {
+impl<B: CryptoBackend> Open<B> {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(alg: Algorithm, key: &[u8], iv: &[u8], pn_key: &[u8])
-                                                            -> Result<Open> {
+                                                        -> Result<Open<B>> {
+        if iv.len() > MAX_NONCE_LEN {
+            return Err(Error::CryptoFail);
+        }
+
+        let mut nonce = [0; MAX_NONCE_LEN];
+        nonce[..iv.len()].copy_from_slice(iv);
+
         Ok(Open {
-            pn_key: unauthenticated_stream::DecryptingKey::new(
-                            alg.get_ring_stream(), &pn_key).unwrap(),
-            key: aead::OpeningKey::new(alg.get_ring_aead(), &key).unwrap(),
-            nonce: Vec::from(iv),
+            pn_key: B::hp_opening_key(alg, pn_key)?,
+            key: B::opening_key(alg, key)?,
+            key_secret: Secret::new(key.to_vec()),
+            pn_key_secret: Secret::new(pn_key.to_vec()),
+            nonce,
+            nonce_len: iv.len(),
             alg,
         })
     }
 
     pub fn open(&self, nonce: &[u8], ad: &[u8], buf: &mut [u8]) -> Result<usize> {
-        let plain = aead::open_in_place(&self.key, nonce, ad, 0, buf)
-                         .map_err(|_| Error::CryptoFail)?;
-
-        Ok(plain.len())
+        B::open(&self.key, nonce, ad, buf)
     }
 
     pub fn open_with_u64_counter(&self, counter: u64, ad: &[u8], buf: &mut [u8])
                                                             -> Result<usize> {
-        let mut counter_nonce: [u8; 12] = [0xba; 12];
-
-        {
-            let mut b = octets::Bytes::new(&mut counter_nonce);
-
-            b.put_u32(0).unwrap();
-            b.put_u64(counter).unwrap();
-        }
+        let mut nonce = [0; MAX_NONCE_LEN];
+        self.derive_nonce(counter, &mut nonce);
 
-        let mut nonce = self.nonce.clone();
+        self.open(&nonce[..self.nonce_len], ad, buf)
+    }
 
-        for i in 0 .. nonce.len() {
-            nonce[i] ^= counter_nonce[i];
+    /// Opens `bufs` in place, deriving each packet's nonce as
+    /// `iv XOR (0u32 || counter_be)` for `counter = start_counter + i`,
+    /// without allocating. `ad_fn(counter, out)` must fill `out` with the
+    /// packet's associated data and return its length.
+    ///
+    /// Each buffer is opened independently: a single bad packet (wrong tag,
+    /// truncated header, etc.) does not abort the rest of the batch, since
+    /// an attacker or a reordered/corrupted packet on the wire shouldn't be
+    /// able to make an entire batch of otherwise-good packets undecryptable.
+    /// `results[i]` holds the outcome for `bufs[i]`; the return value is the
+    /// number of buffers that opened successfully.
+    pub fn open_batch<F>(&self, start_counter: u64, mut ad_fn: F,
+                          bufs: &mut [&mut [u8]], results: &mut [Result<usize>])
+                                        -> usize
+                                        where F: FnMut(u64, &mut [u8]) -> usize {
+        assert_eq!(bufs.len(), results.len());
+
+        let mut nonce = [0; MAX_NONCE_LEN];
+        let mut ad = [0; MAX_AD_LEN];
+        let mut opened = 0;
+
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            let counter = start_counter + i as u64;
+
+            self.derive_nonce(counter, &mut nonce);
+
+            let ad_len = ad_fn(counter, &mut ad);
+
+            results[i] = self.open(&nonce[..self.nonce_len], &ad[..ad_len], buf);
+
+            if results[i].is_ok() {
+                opened += 1;
+            }
         }
 
-        self.open(&nonce, ad, buf)
+        opened
     }
 
     pub fn xor_keystream(&self, nonce: &[u8], buf: &mut [u8]) -> Result<usize> {
-        let plain = unauthenticated_stream::decrypt_in_place(&self.pn_key,
-                        nonce, buf).map_err(|_| Error::CryptoFail)?;
-
-        Ok(plain.len())
+        B::open_keystream(&self.pn_key, nonce, buf)
     }
 
     pub fn alg(&self) -> Algorithm {
         self.alg
     }
+
+    fn derive_nonce(&self, counter: u64, out: &mut [u8; MAX_NONCE_LEN]) {
+        let mut counter_nonce = [0; MAX_NONCE_LEN];
+
+        {
+            let mut b = octets::Bytes::new(&mut counter_nonce[..12]);
+
+            b.put_u32(0).unwrap();
+            b.put_u64(counter).unwrap();
+        }
+
+        for i in 0 .. self.nonce_len {
+            out[i] = self.nonce[i] ^ counter_nonce[i];
+        }
+    }
+}
+
+impl<B: CryptoBackend> Drop for Open<B> {
+    fn drop(&mut self) {
+        for b in self.nonce.iter_mut() {
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
-pub struct Seal {
+pub struct Seal<B: CryptoBackend = RingCryptoBackend> {
     alg: Algorithm,
-    pn_key: unauthenticated_stream::EncryptingKey,
-    key: aead::SealingKey,
-    nonce: Vec<u8>,
+    pn_key: B::HpSealingKey,
+    key: B::SealingKey,
+    // Raw copies of `key`/`pn_key` above, kept solely so the key material is
+    // wiped when `Seal` is dropped: `pn_key`/`key` are opaque backend
+    // objects we have no way to force to zero their own internal state.
+    #[allow(dead_code)]
+    key_secret: Secret,
+    #[allow(dead_code)]
+    pn_key_secret: Secret,
+    nonce: [u8; MAX_NONCE_LEN],
+    nonce_len: usize,
 }
 
-impl Seal {
+impl<B: CryptoBackend> Seal<B> {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(alg: Algorithm, key: &[u8], iv: &[u8], pn_key: &[u8])
-                                                            -> Result<Seal> {
+                                                        -> Result<Seal<B>> {
+        if iv.len() > MAX_NONCE_LEN {
+            return Err(Error::CryptoFail);
+        }
+
+        let mut nonce = [0; MAX_NONCE_LEN];
+        nonce[..iv.len()].copy_from_slice(iv);
+
         Ok(Seal {
-            pn_key: unauthenticated_stream::EncryptingKey::new(
-                            alg.get_ring_stream(), &pn_key).unwrap(),
-            key: aead::SealingKey::new(alg.get_ring_aead(), &key).unwrap(),
-            nonce: Vec::from(iv),
+            pn_key: B::hp_sealing_key(alg, pn_key)?,
+            key: B::sealing_key(alg, key)?,
+            key_secret: Secret::new(key.to_vec()),
+            pn_key_secret: Secret::new(pn_key.to_vec()),
+            nonce,
+            nonce_len: iv.len(),
             alg,
         })
     }
 
     pub fn seal(&self, nonce: &[u8], ad: &[u8], buf: &mut [u8]) -> Result<usize> {
-        let cipher = aead::seal_in_place(&self.key, nonce, ad, buf, self.alg().tag_len())
-                          .map_err(|_| Error::CryptoFail)?;
-
-        Ok(cipher)
+        B::seal(&self.key, nonce, ad, buf, self.alg().tag_len())
     }
 
     pub fn seal_with_u64_counter(&self, counter: u64, ad: &[u8], buf: &mut [u8])
                                                             -> Result<usize> {
-        let mut counter_nonce: [u8; 12] = [0xba; 12];
+        let mut nonce = [0; MAX_NONCE_LEN];
+        self.derive_nonce(counter, &mut nonce);
 
-        {
-            let mut b = octets::Bytes::new(&mut counter_nonce);
-
-            b.put_u32(0).unwrap();
-            b.put_u64(counter).unwrap();
-        }
-
-        let mut nonce = self.nonce.clone();
+        self.seal(&nonce[..self.nonce_len], ad, buf)
+    }
 
-        for i in 0 .. nonce.len() {
-            nonce[i] ^= counter_nonce[i];
+    /// Seals `bufs` in place, deriving each packet's nonce as
+    /// `iv XOR (0u32 || counter_be)` for `counter = start_counter + i`,
+    /// without allocating. `ad_fn(counter, out)` must fill `out` with the
+    /// packet's associated data and return its length.
+    ///
+    /// Each buffer is sealed independently: a single failure (e.g. a buffer
+    /// too short for the tag) does not abort the rest of the batch, for the
+    /// same reason `Open::open_batch` doesn't. `results[i]` holds the
+    /// outcome for `bufs[i]`; the return value is the number of buffers
+    /// that sealed successfully.
+    pub fn seal_batch<F>(&self, start_counter: u64, mut ad_fn: F,
+                          bufs: &mut [&mut [u8]], results: &mut [Result<usize>])
+                                        -> usize
+                                        where F: FnMut(u64, &mut [u8]) -> usize {
+        assert_eq!(bufs.len(), results.len());
+
+        let mut nonce = [0; MAX_NONCE_LEN];
+        let mut ad = [0; MAX_AD_LEN];
+        let mut sealed = 0;
+
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            let counter = start_counter + i as u64;
+
+            self.derive_nonce(counter, &mut nonce);
+
+            let ad_len = ad_fn(counter, &mut ad);
+
+            results[i] = self.seal(&nonce[..self.nonce_len], &ad[..ad_len], buf);
+
+            if results[i].is_ok() {
+                sealed += 1;
+            }
         }
 
-        self.seal(&nonce, ad, buf)
+        sealed
     }
 
     pub fn xor_keystream(&self, nonce: &[u8], buf: &mut [u8]) -> Result<usize> {
-        let plain = unauthenticated_stream::encrypt_in_place(&self.pn_key,
-                        nonce, buf).map_err(|_| Error::CryptoFail)?;
-
-        Ok(plain)
+        B::seal_keystream(&self.pn_key, nonce, buf)
     }
 
     pub fn alg(&self) -> Algorithm {
         self.alg
     }
+
+    fn derive_nonce(&self, counter: u64, out: &mut [u8; MAX_NONCE_LEN]) {
+        let mut counter_nonce = [0; MAX_NONCE_LEN];
+
+        {
+            let mut b = octets::Bytes::new(&mut counter_nonce[..12]);
+
+            b.put_u32(0).unwrap();
+            b.put_u64(counter).unwrap();
+        }
+
+        for i in 0 .. self.nonce_len {
+            out[i] = self.nonce[i] ^ counter_nonce[i];
+        }
+    }
+}
+
+impl<B: CryptoBackend> Drop for Seal<B> {
+    fn drop(&mut self) {
+        for b in self.nonce.iter_mut() {
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
-pub fn derive_initial_key_material(cid: &[u8], is_server: bool)
-                                                    -> Result<(Open, Seal)> {
-    let mut secret: [u8; 32] =  unsafe { std::mem::uninitialized() };
+pub fn derive_initial_key_material<B: CryptoBackend>(cid: &[u8], is_server: bool)
+                                            -> Result<(Open<B>, Seal<B>)> {
+    let mut secret = Secret::zero(32);
 
     let aead = Algorithm::AES128_GCM;
 
     let key_len = aead.key_len();
     let nonce_len = aead.nonce_len();
 
-    let initial_secret = derive_initial_secret(&cid)?;
+    let initial_secret = derive_initial_secret::<B>(&cid);
 
     // Client.
-    let mut client_key = vec![0; key_len];
-    let mut client_iv = vec![0; nonce_len];
-    let mut client_pn_key = vec![0; key_len];
+    let mut client_key = Secret::zero(key_len);
+    let mut client_iv = Secret::zero(nonce_len);
+    let mut client_pn_key = Secret::zero(key_len);
 
-    derive_client_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut client_key)?;
-    derive_pkt_iv(aead, &secret, &mut client_iv)?;
-    derive_hdr_key(aead, &secret, &mut client_pn_key)?;
+    derive_client_initial_secret::<B>(&initial_secret, &mut secret);
+    derive_pkt_key::<B>(aead, &secret, &mut client_key)?;
+    derive_pkt_iv::<B>(aead, &secret, &mut client_iv)?;
+    derive_hdr_key::<B>(aead, &secret, &mut client_pn_key)?;
 
     // Server.
-    let mut server_key = vec![0; key_len];
-    let mut server_iv = vec![0; nonce_len];
-    let mut server_pn_key = vec![0; key_len];
+    let mut server_key = Secret::zero(key_len);
+    let mut server_iv = Secret::zero(nonce_len);
+    let mut server_pn_key = Secret::zero(key_len);
 
-    derive_server_initial_secret(&initial_secret, &mut secret)?;
-    derive_pkt_key(aead, &secret, &mut server_key)?;
-    derive_pkt_iv(aead, &secret, &mut server_iv)?;
-    derive_hdr_key(aead, &secret, &mut server_pn_key)?;
+    derive_server_initial_secret::<B>(&initial_secret, &mut secret);
+    derive_pkt_key::<B>(aead, &secret, &mut server_key)?;
+    derive_pkt_iv::<B>(aead, &secret, &mut server_iv)?;
+    derive_hdr_key::<B>(aead, &secret, &mut server_pn_key)?;
 
     let (open, seal) = if is_server {
         (Open::new(aead, &client_key, &client_iv, &client_pn_key)?,
@@ -271,23 +474,22 @@ pub fn derive_initial_key_material(cid: &[u8], is_server: bool)
     Ok((open, seal))
 }
 
-fn derive_initial_secret(secret: &[u8]) -> Result<hmac::SigningKey> {
-    let salt = hmac::SigningKey::new(&digest::SHA256, &INITIAL_SALT);
-    Ok(hkdf::extract(&salt, secret))
+fn derive_initial_secret<B: CryptoBackend>(secret: &[u8]) -> B::Prk {
+    B::extract(Digest::Sha256, &INITIAL_SALT, secret)
 }
 
-fn derive_client_initial_secret(prk: &hmac::SigningKey, out: &mut [u8]) -> Result<()> {
+fn derive_client_initial_secret<B: CryptoBackend>(prk: &B::Prk, out: &mut [u8]) {
     const LABEL: &[u8] = b"client in";
-    hkdf_expand_label(prk, LABEL, out)
+    hkdf_expand_label::<B>(prk, LABEL, out)
 }
 
-fn derive_server_initial_secret(prk: &hmac::SigningKey, out: &mut [u8]) -> Result<()> {
+fn derive_server_initial_secret<B: CryptoBackend>(prk: &B::Prk, out: &mut [u8]) {
     const LABEL: &[u8] = b"server in";
-    hkdf_expand_label(prk, LABEL, out)
+    hkdf_expand_label::<B>(prk, LABEL, out)
 }
 
-pub fn derive_hdr_key(aead: Algorithm, secret: &[u8], out: &mut [u8])
-                                                                -> Result<()> {
+pub fn derive_hdr_key<B: CryptoBackend>(aead: Algorithm, secret: &[u8],
+                                                out: &mut [u8]) -> Result<()> {
     const LABEL: &[u8] = b"quic hp";
 
     let key_len = aead.key_len();
@@ -296,12 +498,14 @@ pub fn derive_hdr_key(aead: Algorithm, secret: &[u8], out: &mut [u8])
         return Err(Error::CryptoFail);
     }
 
-    let secret = hmac::SigningKey::new(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    let secret = B::prk_from_bytes(aead.digest(), secret);
+    hkdf_expand_label::<B>(&secret, LABEL, &mut out[..key_len]);
+
+    Ok(())
 }
 
-pub fn derive_pkt_key(aead: Algorithm, secret: &[u8], out: &mut [u8])
-                                                                -> Result<()> {
+pub fn derive_pkt_key<B: CryptoBackend>(aead: Algorithm, secret: &[u8],
+                                                out: &mut [u8]) -> Result<()> {
     const LABEL: &[u8] = b"quic key";
 
     let key_len = aead.key_len();
@@ -310,12 +514,14 @@ pub fn derive_pkt_key(aead: Algorithm, secret: &[u8], out: &mut [u8])
         return Err(Error::CryptoFail);
     }
 
-    let secret = hmac::SigningKey::new(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..key_len])
+    let secret = B::prk_from_bytes(aead.digest(), secret);
+    hkdf_expand_label::<B>(&secret, LABEL, &mut out[..key_len]);
+
+    Ok(())
 }
 
-pub fn derive_pkt_iv(aead: Algorithm, secret: &[u8], out: &mut [u8])
-                                                                -> Result<()> {
+pub fn derive_pkt_iv<B: CryptoBackend>(aead: Algorithm, secret: &[u8],
+                                                out: &mut [u8]) -> Result<()> {
     const LABEL: &[u8] = b"quic iv";
 
     let nonce_len = aead.nonce_len();
@@ -324,35 +530,177 @@ pub fn derive_pkt_iv(aead: Algorithm, secret: &[u8], out: &mut [u8])
         return Err(Error::CryptoFail);
     }
 
-    let secret = hmac::SigningKey::new(aead.get_ring_digest(), secret);
-    hkdf_expand_label(&secret, LABEL, &mut out[..nonce_len])
+    let secret = B::prk_from_bytes(aead.digest(), secret);
+    hkdf_expand_label::<B>(&secret, LABEL, &mut out[..nonce_len]);
+
+    Ok(())
 }
 
-fn hkdf_expand_label(prk: &hmac::SigningKey, label: &[u8],  out: &mut [u8])
-                                                            -> Result<()> {
+fn hkdf_expand_label<B: CryptoBackend>(prk: &B::Prk, label: &[u8], out: &mut [u8]) {
     const LABEL_PREFIX: &[u8] = b"tls13 ";
 
-    let mut info: [u8; 256] = unsafe { std::mem::uninitialized() };
+    let mut info: [u8; 256] = [0; 256];
 
     let info_len = {
         let mut b = octets::Bytes::new(&mut info);
 
-        if b.put_u16(out.len() as u16).is_err() ||
-           b.put_u8((LABEL_PREFIX.len() + label.len()) as u8).is_err() ||
-           b.put_bytes(LABEL_PREFIX).is_err() ||
-           b.put_bytes(label).is_err() ||
-           b.put_u8(0).is_err() {
-            return Err(Error::CryptoFail);
-        }
+        b.put_u16(out.len() as u16).unwrap();
+        b.put_u8((LABEL_PREFIX.len() + label.len()) as u8).unwrap();
+        b.put_bytes(LABEL_PREFIX).unwrap();
+        b.put_bytes(label).unwrap();
+        b.put_u8(0).unwrap();
 
         b.off()
     };
 
-    hkdf::expand(prk, &info[..info_len], out);
+    B::expand(prk, &info[..info_len], out);
+}
+
+/// RFC 9001 Section 6.1 key update: derives the next generation's 1-RTT secret
+/// from the current one.
+///
+/// `derive_pkt_key`/`derive_pkt_iv` are then used on the result exactly as
+/// they are on the initial secrets; header-protection keys are intentionally
+/// *not* re-derived here, since `pn_key` carries over across key phases.
+pub fn derive_next_secret<B: CryptoBackend>(aead: Algorithm, current_secret: &[u8])
+                                                                    -> Secret {
+    const LABEL: &[u8] = b"quic ku";
 
-    Ok(())
+    let secret = B::prk_from_bytes(aead.digest(), current_secret);
+
+    let mut next_secret = Secret::zero(aead.digest().len());
+    hkdf_expand_label::<B>(&secret, LABEL, &mut next_secret);
+
+    next_secret
+}
+
+/// Which of the two QUIC 1-RTT key phases is currently in use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyPhase {
+    Zero,
+    One,
+}
+
+impl KeyPhase {
+    pub fn flipped(self) -> KeyPhase {
+        match self {
+            KeyPhase::Zero => KeyPhase::One,
+            KeyPhase::One => KeyPhase::Zero,
+        }
+    }
+}
+
+/// Holds the 1-RTT `Open`/`Seal` pair that's current for a connection, and
+/// rolls them forward on key update (RFC 9001 Section 6).
+///
+/// The previous generation is kept around after a roll so that packets
+/// reordered across the update still decrypt; it's up to the caller to
+/// discard it once it's no longer needed (e.g. after a PTO has elapsed).
+pub struct Keys<B: CryptoBackend = RingCryptoBackend> {
+    aead: Algorithm,
+    phase: KeyPhase,
+
+    open: Open<B>,
+    seal: Seal<B>,
+    open_secret: Secret,
+    seal_secret: Secret,
+
+    // `pn_key`s are carried over unchanged across key updates (RFC 9001
+    // Section 6), so `Keys` keeps its own copies rather than making the
+    // caller hold onto them for the connection's lifetime: caller-owned
+    // storage wouldn't be wiped on drop the way `Open`/`Seal` wipe theirs.
+    open_pn_key: Secret,
+    seal_pn_key: Secret,
+
+    previous: Option<(Open<B>, Seal<B>)>,
 }
 
+impl<B: CryptoBackend> Keys<B> {
+    pub fn new(aead: Algorithm, open: Open<B>, seal: Seal<B>, open_secret: Secret,
+               seal_secret: Secret, open_pn_key: Secret, seal_pn_key: Secret)
+                                                                    -> Keys<B> {
+        Keys {
+            aead,
+            phase: KeyPhase::Zero,
+            open,
+            seal,
+            open_secret,
+            seal_secret,
+            open_pn_key,
+            seal_pn_key,
+            previous: None,
+        }
+    }
+
+    pub fn phase(&self) -> KeyPhase {
+        self.phase
+    }
+
+    pub fn open(&self) -> &Open<B> {
+        &self.open
+    }
+
+    pub fn seal(&self) -> &Seal<B> {
+        &self.seal
+    }
+
+    /// The key pair from before the last `next_keys()`, if any. Reordered
+    /// packets still tagged with the old key phase should be tried against
+    /// this before being dropped.
+    pub fn previous(&self) -> Option<(&Open<B>, &Seal<B>)> {
+        self.previous.as_ref().map(|(open, seal)| (open, seal))
+    }
+
+    /// Drops the previous generation's keys, once reordered old-phase
+    /// packets are no longer expected.
+    pub fn discard_previous(&mut self) {
+        self.previous = None;
+    }
+
+    /// Derives the next generation of keys from the current secrets, flips
+    /// the key phase, and stashes the current generation as `previous()`.
+    /// `pn_key`s are carried over unchanged, per RFC 9001 Section 6, reusing
+    /// the copies `Keys` has held onto since construction.
+    pub fn next_keys(&mut self) -> Result<(&Open<B>, &Seal<B>)> {
+        let aead = self.aead;
+
+        let next_open_secret = derive_next_secret::<B>(aead, &self.open_secret);
+        let next_seal_secret = derive_next_secret::<B>(aead, &self.seal_secret);
+
+        // A key update that comes out identical to the secret it's replacing
+        // would mean packets after the roll are protected with exactly the
+        // same key as before it, defeating the point of updating at all; bail
+        // rather than install keys that don't actually rotate anything. Use a
+        // constant-time compare since these are secrets, not public values.
+        if is_equal(&next_open_secret, &self.open_secret) ||
+           is_equal(&next_seal_secret, &self.seal_secret) {
+            return Err(Error::CryptoFail);
+        }
+
+        let mut open_key = Secret::zero(aead.key_len());
+        let mut open_iv = Secret::zero(aead.nonce_len());
+        derive_pkt_key::<B>(aead, &next_open_secret, &mut open_key)?;
+        derive_pkt_iv::<B>(aead, &next_open_secret, &mut open_iv)?;
+
+        let mut seal_key = Secret::zero(aead.key_len());
+        let mut seal_iv = Secret::zero(aead.nonce_len());
+        derive_pkt_key::<B>(aead, &next_seal_secret, &mut seal_key)?;
+        derive_pkt_iv::<B>(aead, &next_seal_secret, &mut seal_iv)?;
+
+        let next_open = Open::new(aead, &open_key, &open_iv, &self.open_pn_key)?;
+        let next_seal = Seal::new(aead, &seal_key, &seal_iv, &self.seal_pn_key)?;
+
+        let prev_open = std::mem::replace(&mut self.open, next_open);
+        let prev_seal = std::mem::replace(&mut self.seal, next_seal);
+
+        self.open_secret = next_open_secret;
+        self.seal_secret = next_seal_secret;
+        self.phase = self.phase.flipped();
+        self.previous = Some((prev_open, prev_seal));
+
+        Ok((&self.open, &self.seal))
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -369,10 +717,10 @@ mod tests {
 
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret = derive_initial_secret(&dcid).unwrap();
+        let initial_secret = derive_initial_secret::<RingCryptoBackend>(&dcid);
 
         // Client.
-        assert!(derive_client_initial_secret(&initial_secret, &mut secret).is_ok());
+        derive_client_initial_secret::<RingCryptoBackend>(&initial_secret, &mut secret);
         let expected_client_initial_secret: [u8; 32] = [
             0x0c, 0x74, 0xbb, 0x95, 0xa1, 0x04, 0x8e, 0x52,
             0xef, 0x3b, 0x72, 0xe1, 0x28, 0x89, 0x35, 0x1c,
@@ -381,21 +729,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key::<RingCryptoBackend>(aead, &secret, &mut pkt_key).is_ok());
         let expected_client_pkt_key: [u8; 16] = [
             0x86, 0xd1, 0x83, 0x04, 0x80, 0xb4, 0x0f, 0x86,
             0xcf, 0x9d, 0x68, 0xdc, 0xad, 0xf3, 0x5d, 0xfe,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv::<RingCryptoBackend>(aead, &secret, &mut pkt_iv).is_ok());
         let expected_client_pkt_iv: [u8; 12] = [
             0x12, 0xf3, 0x93, 0x8a, 0xca, 0x34, 0xaa, 0x02,
             0x54, 0x31, 0x63, 0xd4,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key::<RingCryptoBackend>(aead, &secret, &mut hdr_key).is_ok());
         let expected_cliet_hdr_key: [u8; 16] = [
             0xcd, 0x25, 0x3a, 0x36, 0xff, 0x93, 0x93, 0x7c,
             0x46, 0x93, 0x84, 0xa8, 0x23, 0xaf, 0x6c, 0x56,
@@ -403,7 +751,7 @@ mod tests {
         assert_eq!(&hdr_key, &expected_cliet_hdr_key);
 
         // Server.
-        assert!(derive_server_initial_secret(&initial_secret, &mut secret).is_ok());
+        derive_server_initial_secret::<RingCryptoBackend>(&initial_secret, &mut secret);
         let expected_server_initial_secret: [u8; 32] = [
             0x4c, 0x9e, 0xdf, 0x24, 0xb0, 0xe5, 0xe5, 0x06,
             0xdd, 0x3b, 0xfa, 0x4e, 0x0a, 0x03, 0x11, 0xe8,
@@ -412,21 +760,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key::<RingCryptoBackend>(aead, &secret, &mut pkt_key).is_ok());
         let expected_server_pkt_key: [u8; 16] = [
             0x2c, 0x78, 0x63, 0x3e, 0x20, 0x6e, 0x99, 0xad,
             0x25, 0x19, 0x64, 0xf1, 0x9f, 0x6d, 0xcd, 0x6d,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv::<RingCryptoBackend>(aead, &secret, &mut pkt_iv).is_ok());
         let expected_server_pkt_iv: [u8; 12] = [
             0x7b, 0x50, 0xbf, 0x36, 0x98, 0xa0, 0x6d, 0xfa,
             0xbf, 0x75, 0xf2, 0x87,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key::<RingCryptoBackend>(aead, &secret, &mut hdr_key).is_ok());
         let expected_server_hdr_key: [u8; 16] = [
             0x25, 0x79, 0xd8, 0x69, 0x6f, 0x85, 0xed, 0xa6,
             0x8d, 0x35, 0x02, 0xb6, 0x55, 0x96, 0x58, 0x6b,
@@ -445,10 +793,10 @@ mod tests {
 
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret = derive_initial_secret(&dcid).unwrap();
+        let initial_secret = derive_initial_secret::<RingCryptoBackend>(&dcid);
 
         // Client.
-        assert!(derive_client_initial_secret(&initial_secret, &mut secret).is_ok());
+        derive_client_initial_secret::<RingCryptoBackend>(&initial_secret, &mut secret);
         let expected_client_initial_secret: [u8; 32] = [
             0x8a, 0x35, 0x15, 0xa1, 0x4a, 0xe3, 0xc3, 0x1b,
             0x9c, 0x2d, 0x6d, 0x5b, 0xc5, 0x85, 0x38, 0xca,
@@ -457,21 +805,21 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_client_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key::<RingCryptoBackend>(aead, &secret, &mut pkt_key).is_ok());
         let expected_client_pkt_key: [u8; 16] = [
             0x98, 0xb0, 0xd7, 0xe5, 0xe7, 0xa4, 0x02, 0xc6,
             0x7c, 0x33, 0xf3, 0x50, 0xfa, 0x65, 0xea, 0x54,
         ];
         assert_eq!(&pkt_key, &expected_client_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv::<RingCryptoBackend>(aead, &secret, &mut pkt_iv).is_ok());
         let expected_client_pkt_iv: [u8; 12] = [
             0x19, 0xe9, 0x43, 0x87, 0x80, 0x5e, 0xb0, 0xb4,
             0x6c, 0x03, 0xa7, 0x88,
         ];
         assert_eq!(&pkt_iv, &expected_client_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key::<RingCryptoBackend>(aead, &secret, &mut hdr_key).is_ok());
         let expected_cliet_hdr_key: [u8; 16] = [
             0x0e, 0xdd, 0x98, 0x2a, 0x6a, 0xc5, 0x27, 0xf2,
             0xed, 0xdc, 0xbb, 0x73, 0x48, 0xde, 0xa5, 0xd7,
@@ -479,7 +827,7 @@ mod tests {
         assert_eq!(&hdr_key, &expected_cliet_hdr_key);
 
         // Server.
-        assert!(derive_server_initial_secret(&initial_secret, &mut secret).is_ok());
+        derive_server_initial_secret::<RingCryptoBackend>(&initial_secret, &mut secret);
         let expected_server_initial_secret: [u8; 32] = [
             0x47, 0xb2, 0xea, 0xea, 0x6c, 0x26, 0x6e, 0x32,
             0xc0, 0x69, 0x7a, 0x9e, 0x2a, 0x89, 0x8b, 0xdf,
@@ -488,25 +836,144 @@ mod tests {
         ];
         assert_eq!(&secret, &expected_server_initial_secret);
 
-        assert!(derive_pkt_key(aead, &secret, &mut pkt_key).is_ok());
+        assert!(derive_pkt_key::<RingCryptoBackend>(aead, &secret, &mut pkt_key).is_ok());
         let expected_server_pkt_key: [u8; 16] = [
             0x9a, 0x8b, 0xe9, 0x02, 0xa9, 0xbd, 0xd9, 0x1d,
             0x16, 0x06, 0x4c, 0xa1, 0x18, 0x04, 0x5f, 0xb4,
         ];
         assert_eq!(&pkt_key, &expected_server_pkt_key);
 
-        assert!(derive_pkt_iv(aead, &secret, &mut pkt_iv).is_ok());
+        assert!(derive_pkt_iv::<RingCryptoBackend>(aead, &secret, &mut pkt_iv).is_ok());
         let expected_server_pkt_iv: [u8; 12] = [
             0x0a, 0x82, 0x08, 0x6d, 0x32, 0x20, 0x5b, 0xa2,
             0x22, 0x41, 0xd8, 0xdc,
         ];
         assert_eq!(&pkt_iv, &expected_server_pkt_iv);
 
-        assert!(derive_hdr_key(aead, &secret, &mut hdr_key).is_ok());
+        assert!(derive_hdr_key::<RingCryptoBackend>(aead, &secret, &mut hdr_key).is_ok());
         let expected_server_hdr_key: [u8; 16] = [
             0x94, 0xb9, 0x45, 0x2d, 0x2b, 0x3c, 0x7c, 0x7f,
             0x6d, 0xa7, 0xfd, 0xd8, 0x59, 0x35, 0x37, 0xfd,
         ];
         assert_eq!(&hdr_key, &expected_server_hdr_key);
     }
+
+    #[test]
+    fn batch_reports_per_packet_failures() {
+        let dcid: [u8; 8] = [0xc6, 0x54, 0xef, 0xd8, 0xa3, 0x1b, 0x47, 0x92];
+
+        let (client_open, _): (Open, Seal) =
+            derive_initial_key_material(&dcid, false).unwrap();
+        let (_, server_seal): (Open, Seal) =
+            derive_initial_key_material(&dcid, true).unwrap();
+
+        let tag_len = server_seal.alg().tag_len();
+
+        let mut good: Vec<u8> = b"good packet".to_vec();
+        good.extend(std::iter::repeat(0).take(tag_len));
+
+        let mut bad: Vec<u8> = b"bad packet..".to_vec();
+        bad.extend(std::iter::repeat(0).take(tag_len));
+
+        let mut results = [Ok(0), Ok(0)];
+        let sealed = server_seal.seal_batch(
+            0, |_counter, ad| { ad[..3].copy_from_slice(b"ad1"); 3 },
+            &mut [good.as_mut_slice(), bad.as_mut_slice()], &mut results);
+        assert_eq!(sealed, 2);
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        // Corrupt the second packet so its tag no longer verifies, but the
+        // first one stays good.
+        let last = bad.len() - 1;
+        bad[last] ^= 0xff;
+
+        let mut results = [Ok(0), Ok(0)];
+        let opened = client_open.open_batch(
+            0, |_counter, ad| { ad[..3].copy_from_slice(b"ad1"); 3 },
+            &mut [good.as_mut_slice(), bad.as_mut_slice()], &mut results);
+
+        assert_eq!(opened, 1);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn key_update_round_trip() {
+        let aead = Algorithm::AES128_GCM;
+        let tag_len = aead.tag_len();
+
+        let client_secret = vec![0x11u8; 32];
+        let server_secret = vec![0x22u8; 32];
+
+        let derive = |secret: &[u8]| -> (Secret, Secret, Secret) {
+            let mut key = Secret::zero(aead.key_len());
+            let mut iv = Secret::zero(aead.nonce_len());
+            let mut hdr_key = Secret::zero(aead.key_len());
+
+            derive_pkt_key::<RingCryptoBackend>(aead, secret, &mut key).unwrap();
+            derive_pkt_iv::<RingCryptoBackend>(aead, secret, &mut iv).unwrap();
+            derive_hdr_key::<RingCryptoBackend>(aead, secret, &mut hdr_key).unwrap();
+
+            (key, iv, hdr_key)
+        };
+
+        let (client_key, client_iv, client_hdr_key) = derive(&client_secret);
+        let (server_key, server_iv, server_hdr_key) = derive(&server_secret);
+
+        let client_open: Open =
+            Open::new(aead, &server_key, &server_iv, &server_hdr_key).unwrap();
+        let client_seal: Seal =
+            Seal::new(aead, &client_key, &client_iv, &client_hdr_key).unwrap();
+        let mut client_keys = Keys::new(
+            aead, client_open, client_seal,
+            Secret::new(server_secret.clone()), Secret::new(client_secret.clone()),
+            Secret::new(server_hdr_key.to_vec()), Secret::new(client_hdr_key.to_vec()));
+
+        let server_open: Open =
+            Open::new(aead, &client_key, &client_iv, &client_hdr_key).unwrap();
+        let server_seal: Seal =
+            Seal::new(aead, &server_key, &server_iv, &server_hdr_key).unwrap();
+        let mut server_keys = Keys::new(
+            aead, server_open, server_seal,
+            Secret::new(client_secret.clone()), Secret::new(server_secret.clone()),
+            Secret::new(client_hdr_key.to_vec()), Secret::new(server_hdr_key.to_vec()));
+
+        // Sanity check the pre-update keys actually talk to each other.
+        let mut pkt = b"hello before update".to_vec();
+        pkt.extend(std::iter::repeat(0).take(tag_len));
+        let ct_len = client_keys.seal().seal_with_u64_counter(0, b"ad", &mut pkt).unwrap();
+        pkt.truncate(ct_len);
+        let pt_len = server_keys.open().open_with_u64_counter(0, b"ad", &mut pkt).unwrap();
+        assert_eq!(&pkt[..pt_len], b"hello before update");
+
+        assert_eq!(client_keys.phase(), KeyPhase::Zero);
+
+        // pn_key carries over unchanged across the update, per RFC 9001
+        // Section 6.
+        client_keys.next_keys().unwrap();
+        server_keys.next_keys().unwrap();
+
+        assert_eq!(client_keys.phase(), KeyPhase::One);
+        assert_eq!(server_keys.phase(), KeyPhase::One);
+
+        // The new generation talks to itself too.
+        let mut pkt = b"hello after update".to_vec();
+        pkt.extend(std::iter::repeat(0).take(tag_len));
+        let ct_len = client_keys.seal().seal_with_u64_counter(0, b"ad", &mut pkt).unwrap();
+        pkt.truncate(ct_len);
+        let pt_len = server_keys.open().open_with_u64_counter(0, b"ad", &mut pkt).unwrap();
+        assert_eq!(&pkt[..pt_len], b"hello after update");
+
+        // A packet reordered across the update, still tagged with the old
+        // key phase, decrypts against `previous()`.
+        let (_, prev_client_seal) = client_keys.previous().unwrap();
+        let (prev_server_open, _) = server_keys.previous().unwrap();
+
+        let mut pkt = b"reordered old-phase packet".to_vec();
+        pkt.extend(std::iter::repeat(0).take(tag_len));
+        let ct_len = prev_client_seal.seal_with_u64_counter(1, b"ad", &mut pkt).unwrap();
+        pkt.truncate(ct_len);
+        let pt_len = prev_server_open.open_with_u64_counter(1, b"ad", &mut pkt).unwrap();
+        assert_eq!(&pkt[..pt_len], b"reordered old-phase packet");
+    }
 }
@@ -0,0 +1,141 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The default `CryptoBackend`, implemented on top of *ring*.
+
+use ring::aead;
+use ring::digest;
+use ring::hkdf;
+use ring::hmac;
+use ring::unauthenticated_stream;
+
+use crate::Result;
+use crate::Error;
+
+use super::Algorithm;
+use super::CryptoBackend;
+use super::Digest;
+
+fn ring_aead(alg: Algorithm) -> &'static aead::Algorithm {
+    match alg {
+        Algorithm::AES128_GCM => &aead::AES_128_GCM,
+        Algorithm::AES256_GCM => &aead::AES_256_GCM,
+        Algorithm::ChaCha20_Poly1305 => &aead::CHACHA20_POLY1305,
+        Algorithm::Null => panic!("Not a valid AEAD"),
+    }
+}
+
+fn ring_stream(alg: Algorithm) -> &'static unauthenticated_stream::Algorithm {
+    match alg {
+        Algorithm::AES128_GCM => &unauthenticated_stream::AES_128_CTR,
+        Algorithm::AES256_GCM => &unauthenticated_stream::AES_256_CTR,
+        Algorithm::ChaCha20_Poly1305 => &unauthenticated_stream::CHACHA20,
+        Algorithm::Null => panic!("Not a valid AEAD"),
+    }
+}
+
+fn ring_digest(digest: Digest) -> &'static digest::Algorithm {
+    match digest {
+        Digest::Sha256 => &digest::SHA256,
+        Digest::Sha384 => &digest::SHA384,
+    }
+}
+
+/// The `CryptoBackend` backed by *ring*.
+///
+/// This is the only backend the crate ships today, but the AEAD seal/open,
+/// header-protection keystream and HKDF extract/expand are all reached
+/// through the `CryptoBackend` trait so an NSS or OpenSSL-backed
+/// implementation can be dropped in later without touching `Open`/`Seal`.
+pub struct RingCryptoBackend;
+
+impl CryptoBackend for RingCryptoBackend {
+    type OpeningKey = aead::OpeningKey;
+    type SealingKey = aead::SealingKey;
+    type HpOpeningKey = unauthenticated_stream::DecryptingKey;
+    type HpSealingKey = unauthenticated_stream::EncryptingKey;
+    type Prk = hmac::SigningKey;
+
+    fn opening_key(alg: Algorithm, key: &[u8]) -> Result<Self::OpeningKey> {
+        aead::OpeningKey::new(ring_aead(alg), key).map_err(|_| Error::CryptoFail)
+    }
+
+    fn sealing_key(alg: Algorithm, key: &[u8]) -> Result<Self::SealingKey> {
+        aead::SealingKey::new(ring_aead(alg), key).map_err(|_| Error::CryptoFail)
+    }
+
+    fn hp_opening_key(alg: Algorithm, key: &[u8]) -> Result<Self::HpOpeningKey> {
+        unauthenticated_stream::DecryptingKey::new(ring_stream(alg), key)
+            .map_err(|_| Error::CryptoFail)
+    }
+
+    fn hp_sealing_key(alg: Algorithm, key: &[u8]) -> Result<Self::HpSealingKey> {
+        unauthenticated_stream::EncryptingKey::new(ring_stream(alg), key)
+            .map_err(|_| Error::CryptoFail)
+    }
+
+    fn open(key: &Self::OpeningKey, nonce: &[u8], ad: &[u8], buf: &mut [u8])
+                                                            -> Result<usize> {
+        let plain = aead::open_in_place(key, nonce, ad, 0, buf)
+                         .map_err(|_| Error::CryptoFail)?;
+
+        Ok(plain.len())
+    }
+
+    fn seal(key: &Self::SealingKey, nonce: &[u8], ad: &[u8], buf: &mut [u8],
+                                            tag_len: usize) -> Result<usize> {
+        aead::seal_in_place(key, nonce, ad, buf, tag_len)
+             .map_err(|_| Error::CryptoFail)
+    }
+
+    fn open_keystream(key: &Self::HpOpeningKey, nonce: &[u8], buf: &mut [u8])
+                                                            -> Result<usize> {
+        let plain = unauthenticated_stream::decrypt_in_place(key, nonce, buf)
+                         .map_err(|_| Error::CryptoFail)?;
+
+        Ok(plain.len())
+    }
+
+    fn seal_keystream(key: &Self::HpSealingKey, nonce: &[u8], buf: &mut [u8])
+                                                            -> Result<usize> {
+        unauthenticated_stream::encrypt_in_place(key, nonce, buf)
+             .map_err(|_| Error::CryptoFail)
+    }
+
+    fn extract(digest: Digest, salt: &[u8], ikm: &[u8]) -> Self::Prk {
+        let salt = hmac::SigningKey::new(ring_digest(digest), salt);
+        hkdf::extract(&salt, ikm)
+    }
+
+    fn prk_from_bytes(digest: Digest, bytes: &[u8]) -> Self::Prk {
+        hmac::SigningKey::new(ring_digest(digest), bytes)
+    }
+
+    fn expand(prk: &Self::Prk, info: &[u8], out: &mut [u8]) {
+        hkdf::expand(prk, info, out);
+    }
+}
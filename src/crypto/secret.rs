@@ -0,0 +1,102 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::atomic;
+
+/// A byte buffer holding key material that is wiped as soon as it's dropped,
+/// so secrets don't linger in freed memory for the lifetime of the process.
+///
+/// Writes go through `ptr::write_volatile` with a trailing compiler fence,
+/// so the zeroing can't be optimized away the way a plain loop over `buf[i]
+/// = 0` can.
+pub struct Secret {
+    buf: Vec<u8>,
+}
+
+impl Secret {
+    pub fn new(buf: Vec<u8>) -> Secret {
+        Secret { buf }
+    }
+
+    pub fn zero(len: usize) -> Secret {
+        Secret { buf: vec![0; len] }
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        for b in self.buf.iter_mut() {
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+}
+
+/// Compares `a` and `b` in constant time, for use on secrets/tags where a
+/// data-dependent early-out (as in `==`) would leak timing information.
+pub fn is_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+
+    for i in 0 .. a.len() {
+        diff |= a[i] ^ b[i];
+    }
+
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_equal_compares_bytes_and_length() {
+        assert!(is_equal(b"abcdef", b"abcdef"));
+        assert!(!is_equal(b"abcdef", b"abcdeg"));
+        assert!(!is_equal(b"abcdef", b"abc"));
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright
+//       notice, this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Hybrid Public Key Encryption ([RFC 9180]), base mode only.
+//!
+//! This only implements what Encrypted ClientHello needs: single-shot
+//! `seal()`/`open()` using `DHKEM(X25519, HKDF-SHA256)` as the KEM, and
+//! either `AES128_GCM` or `ChaCha20_Poly1305` as the AEAD. The key schedule
+//! is built on top of [`crate::crypto`]'s HKDF and AEAD primitives, always
+//! instantiated with `RingCryptoBackend`; `psk_id_hash`/`info_hash` and the
+//! X25519 key agreement call *ring* directly.
+//!
+//! [RFC 9180]: https://www.rfc-editor.org/rfc/rfc9180
+
+use ring::agreement;
+use ring::digest;
+use ring::hmac;
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+
+use crate::Error;
+use crate::Result;
+
+use crate::crypto::Algorithm;
+use crate::crypto::CryptoBackend;
+use crate::crypto::Digest;
+use crate::crypto::Open;
+use crate::crypto::RingCryptoBackend;
+use crate::crypto::Seal;
+
+use crate::octets;
+
+const NPK: usize = 32; // X25519 public key length.
+const NSECRET: usize = 32; // DHKEM(X25519, HKDF-SHA256) shared secret length.
+
+/// A KEM/KDF/AEAD suite identifying an HPKE ciphersuite.
+///
+/// Only the combinations needed for ECH are exposed: the KEM is always
+/// `DHKEM(X25519, HKDF-SHA256)` (`kem_id = 0x0020`), the KDF is always
+/// HKDF-SHA256 (`kdf_id = 0x0001`), and `aead` picks the AEAD id.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Suite {
+    pub aead: Algorithm,
+}
+
+impl Suite {
+    const KEM_ID: u16 = 0x0020;
+    const KDF_ID: u16 = 0x0001;
+
+    fn aead_id(self) -> Result<u16> {
+        match self.aead {
+            Algorithm::AES128_GCM => Ok(0x0001),
+            Algorithm::ChaCha20_Poly1305 => Ok(0x0003),
+            _ => Err(Error::CryptoFail),
+        }
+    }
+
+    /// The KEM-only `suite_id` used *inside* `DHKEM::ExtractAndExpand`
+    /// (RFC 9180 Section 4.1), i.e. `"KEM" || I2OSP(kem_id, 2)`. This is
+    /// distinct from (and shorter than) the HPKE-level `suite_id` below —
+    /// the two must not be conflated, or the derived KEM shared secret
+    /// won't match any other RFC 9180 implementation's.
+    fn kem_suite_id() -> [u8; 5] {
+        let mut suite_id = [0; 5];
+
+        let mut b = octets::Bytes::new(&mut suite_id);
+        b.put_bytes(b"KEM").unwrap();
+        b.put_u16(Self::KEM_ID).unwrap();
+
+        suite_id
+    }
+
+    /// The HPKE-level `suite_id` used by the Section 5.1 key schedule's
+    /// `LabeledExtract`/`LabeledExpand` calls, i.e. `"HPKE" ||
+    /// I2OSP(kem_id, 2) || I2OSP(kdf_id, 2) || I2OSP(aead_id, 2)`.
+    fn suite_id(self) -> Result<[u8; 10]> {
+        let mut suite_id = [0; 10];
+
+        let mut b = octets::Bytes::new(&mut suite_id);
+        b.put_bytes(b"HPKE").unwrap();
+        b.put_u16(Self::KEM_ID).unwrap();
+        b.put_u16(Self::KDF_ID).unwrap();
+        b.put_u16(self.aead_id()?).unwrap();
+
+        Ok(suite_id)
+    }
+}
+
+/// `"HPKE-v1" || suite_id || label || ikm`, the input shared by
+/// `LabeledExtract` and the raw-bytes variant used for `psk_id_hash`/
+/// `info_hash` below. `suite_id` is passed in rather than derived here,
+/// since callers inside `DHKEM::ExtractAndExpand` and callers inside the
+/// Section 5.1 key schedule each use a different `suite_id` encoding.
+fn labeled_ikm(suite_id: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let mut labeled_ikm = Vec::with_capacity(
+        7 + suite_id.len() + label.len() + ikm.len());
+    labeled_ikm.extend_from_slice(b"HPKE-v1");
+    labeled_ikm.extend_from_slice(suite_id);
+    labeled_ikm.extend_from_slice(label);
+    labeled_ikm.extend_from_slice(ikm);
+
+    labeled_ikm
+}
+
+/// `LabeledExtract(salt, label, ikm) =
+///      Extract(salt, "HPKE-v1" || suite_id || label || ikm)`.
+fn labeled_extract<B: CryptoBackend>(suite_id: &[u8], salt: &[u8], label: &[u8],
+                                      ikm: &[u8]) -> B::Prk {
+    let labeled_ikm = labeled_ikm(suite_id, label, ikm);
+
+    B::extract(Digest::Sha256, salt, &labeled_ikm)
+}
+
+/// Same as `labeled_extract`, but returns the raw `LabeledExtract` output
+/// bytes instead of an opaque `CryptoBackend::Prk`. `psk_id_hash`/
+/// `info_hash` (RFC 9180 Section 5.1) are *used* as byte strings folded into
+/// `key_schedule_context` rather than fed back into `LabeledExpand`, so they
+/// need to leave HMAC as bytes, not as a backend key handle.
+fn labeled_extract_bytes(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8])
+                                                                -> Vec<u8> {
+    let labeled_ikm = labeled_ikm(suite_id, label, ikm);
+
+    let key = hmac::SigningKey::new(&digest::SHA256, salt);
+
+    Vec::from(hmac::sign(&key, &labeled_ikm).as_ref())
+}
+
+/// `LabeledExpand(prk, label, info, len) =
+///      Expand(prk, I2OSP(len, 2) || "HPKE-v1" || suite_id || label || info,
+///             len)`.
+fn labeled_expand<B: CryptoBackend>(suite_id: &[u8], prk: &B::Prk, label: &[u8],
+                                     info: &[u8], out: &mut [u8]) {
+    let mut labeled_info = Vec::with_capacity(
+        2 + 7 + suite_id.len() + label.len() + info.len());
+    labeled_info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    labeled_info.extend_from_slice(b"HPKE-v1");
+    labeled_info.extend_from_slice(suite_id);
+    labeled_info.extend_from_slice(label);
+    labeled_info.extend_from_slice(info);
+
+    B::expand(prk, &labeled_info, out);
+}
+
+/// `DHKEM(X25519, HKDF-SHA256)::Encap`: generates an ephemeral X25519
+/// keypair, returns the encapsulated key `enc` (the ephemeral public key)
+/// and the KEM shared secret.
+fn encap(pk_r: &[u8]) -> Result<(Vec<u8>, [u8; NSECRET])> {
+    let rng = SystemRandom::new();
+
+    let sk_e = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+                    .map_err(|_| Error::CryptoFail)?;
+
+    let mut enc = vec![0; NPK];
+    let enc_len = sk_e.compute_public_key(&mut enc)
+                       .map_err(|_| Error::CryptoFail)?;
+    enc.truncate(enc_len);
+
+    let peer_pk = untrusted::Input::from(pk_r);
+
+    let shared_secret = agreement::agree_ephemeral(
+        sk_e, &agreement::X25519, peer_pk, Error::CryptoFail,
+        |dh| Ok(Vec::from(dh)))?;
+
+    let mut kem_context = Vec::with_capacity(enc.len() + pk_r.len());
+    kem_context.extend_from_slice(&enc);
+    kem_context.extend_from_slice(pk_r);
+
+    Ok((enc, extract_and_expand(&shared_secret, &kem_context)?))
+}
+
+fn extract_and_expand(dh: &[u8], kem_context: &[u8]) -> Result<[u8; NSECRET]> {
+    // DHKEM::ExtractAndExpand (RFC 9180 Section 4.1) uses the KEM-only
+    // suite_id, *not* the HPKE-level one the Section 5.1 key schedule uses.
+    let kem_suite_id = Suite::kem_suite_id();
+
+    let eae_prk = labeled_extract::<RingCryptoBackend>(&kem_suite_id, b"", b"eae_prk", dh);
+
+    let mut shared_secret = [0; NSECRET];
+    labeled_expand::<RingCryptoBackend>(
+        &kem_suite_id, &eae_prk, b"shared_secret", kem_context, &mut shared_secret);
+
+    Ok(shared_secret)
+}
+
+/// The HPKE key schedule (base mode, `mode = 0x00`, no PSK).
+struct Context {
+    key: Vec<u8>,
+    base_nonce: Vec<u8>,
+}
+
+impl Context {
+    fn new(suite: Suite, shared_secret: &[u8; NSECRET], info: &[u8])
+                                                            -> Result<Context> {
+        let nk = suite.aead.key_len();
+        let nn = suite.aead.nonce_len();
+
+        let suite_id = suite.suite_id()?;
+
+        // RFC 9180 Section 5.1: psk_id_hash/info_hash *are* the LabeledExtract
+        // output bytes, not a further LabeledExpand of them.
+        let psk_id_hash_out = labeled_extract_bytes(&suite_id, b"", b"psk_id_hash", b"");
+        let info_hash_out = labeled_extract_bytes(&suite_id, b"", b"info_hash", info);
+
+        let mut key_schedule_context = Vec::with_capacity(
+            1 + psk_id_hash_out.len() + info_hash_out.len());
+        key_schedule_context.push(0x00u8); // mode_base
+        key_schedule_context.extend_from_slice(&psk_id_hash_out);
+        key_schedule_context.extend_from_slice(&info_hash_out);
+
+        let secret = labeled_extract::<RingCryptoBackend>(
+            &suite_id, shared_secret, b"secret", b"");
+
+        let mut key = vec![0; nk];
+        labeled_expand::<RingCryptoBackend>(
+            &suite_id, &secret, b"key", &key_schedule_context, &mut key);
+
+        let mut base_nonce = vec![0; nn];
+        labeled_expand::<RingCryptoBackend>(
+            &suite_id, &secret, b"base_nonce", &key_schedule_context, &mut base_nonce);
+
+        Ok(Context { key, base_nonce })
+    }
+
+    /// The per-message nonce for sequence number 0, i.e. `base_nonce`. HPKE
+    /// XORs in the sequence number for subsequent messages, but single-shot
+    /// `seal()`/`open()` only ever use sequence number 0.
+    fn nonce(&self) -> &[u8] {
+        &self.base_nonce
+    }
+}
+
+/// Encrypts `pt` to the recipient with public key `pk_r`, returning the
+/// encapsulated key `enc` and the ciphertext.
+pub fn seal(suite: Suite, pk_r: &[u8], info: &[u8], aad: &[u8], pt: &[u8])
+                                                -> Result<(Vec<u8>, Vec<u8>)> {
+    let (enc, shared_secret) = encap(pk_r)?;
+
+    let ctx = Context::new(suite, &shared_secret, info)?;
+
+    let pn_key = vec![0; suite.aead.key_len()];
+    let sealer = Seal::<RingCryptoBackend>::new(
+        suite.aead, &ctx.key, ctx.nonce(), &pn_key)?;
+
+    let mut buf = Vec::with_capacity(pt.len() + suite.aead.tag_len());
+    buf.extend_from_slice(pt);
+    buf.extend(std::iter::repeat(0).take(suite.aead.tag_len()));
+
+    let ct_len = sealer.seal(ctx.nonce(), aad, &mut buf)?;
+    buf.truncate(ct_len);
+
+    Ok((enc, buf))
+}
+
+/// `DHKEM(X25519, HKDF-SHA256)::Decap`, recovering the KEM shared secret
+/// the sender derived in `encap()` above.
+fn decap(sk_r: &[u8], enc: &[u8]) -> Result<[u8; NSECRET]> {
+    let sk_r = agreement::EphemeralPrivateKey::from_bytes(&agreement::X25519, sk_r)
+                    .map_err(|_| Error::CryptoFail)?;
+
+    let mut pk_r = vec![0; NPK];
+    let pk_r_len = sk_r.compute_public_key(&mut pk_r)
+                        .map_err(|_| Error::CryptoFail)?;
+    pk_r.truncate(pk_r_len);
+
+    let peer_pk = untrusted::Input::from(enc);
+
+    let shared_secret = agreement::agree_ephemeral(
+        sk_r, &agreement::X25519, peer_pk, Error::CryptoFail,
+        |dh| Ok(Vec::from(dh)))?;
+
+    let mut kem_context = Vec::with_capacity(enc.len() + pk_r.len());
+    kem_context.extend_from_slice(enc);
+    kem_context.extend_from_slice(&pk_r);
+
+    extract_and_expand(&shared_secret, &kem_context)
+}
+
+/// Decrypts `ct`, given the recipient's private key `sk_r` and the
+/// encapsulated key `enc` that came with the ciphertext.
+pub fn open(suite: Suite, sk_r: &[u8], enc: &[u8], info: &[u8], aad: &[u8],
+                                                    ct: &[u8]) -> Result<Vec<u8>> {
+    let shared_secret = decap(sk_r, enc)?;
+
+    let ctx = Context::new(suite, &shared_secret, info)?;
+
+    let pn_key = vec![0; suite.aead.key_len()];
+    let opener = Open::<RingCryptoBackend>::new(
+        suite.aead, &ctx.key, ctx.nonce(), &pn_key)?;
+
+    let mut buf = Vec::from(ct);
+    let pt_len = opener.open(ctx.nonce(), aad, &mut buf)?;
+    buf.truncate(pt_len);
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The KEM-only suite_id (RFC 9180 Section 4.1) must stay the 5-byte
+    /// "KEM" || kem_id encoding, distinct from the 10-byte HPKE-level one
+    /// below -- conflating the two silently breaks interop with any other
+    /// RFC 9180 implementation without failing a same-process round trip.
+    #[test]
+    fn kem_suite_id_is_kem_prefixed() {
+        assert_eq!(Suite::kem_suite_id(), *b"KEM\x00\x20");
+    }
+
+    #[test]
+    fn hpke_suite_id_is_hpke_prefixed() {
+        let suite = Suite { aead: Algorithm::AES128_GCM };
+        assert_eq!(suite.suite_id().unwrap(), *b"HPKE\x00\x20\x00\x01\x00\x01");
+
+        let suite = Suite { aead: Algorithm::ChaCha20_Poly1305 };
+        assert_eq!(suite.suite_id().unwrap(), *b"HPKE\x00\x20\x00\x01\x00\x03");
+    }
+
+    /// A fixed X25519 keypair for the recipient, so tests are deterministic.
+    fn test_keypair() -> (Vec<u8>, Vec<u8>) {
+        let sk_r: [u8; 32] = [0x42; 32];
+
+        let sk = agreement::EphemeralPrivateKey::from_bytes(&agreement::X25519, &sk_r)
+                      .unwrap();
+
+        let mut pk_r = vec![0; NPK];
+        let pk_r_len = sk.compute_public_key(&mut pk_r).unwrap();
+        pk_r.truncate(pk_r_len);
+
+        (sk_r.to_vec(), pk_r)
+    }
+
+    #[test]
+    fn seal_open_roundtrip_aes128_gcm() {
+        let (sk_r, pk_r) = test_keypair();
+
+        let suite = Suite { aead: Algorithm::AES128_GCM };
+        let info = b"quiche ech test";
+        let aad = b"associated data";
+        let pt = b"a secret message";
+
+        let (enc, ct) = seal(suite, &pk_r, info, aad, pt).unwrap();
+        let recovered = open(suite, &sk_r, &enc, info, aad, &ct).unwrap();
+
+        assert_eq!(&recovered, pt);
+    }
+
+    #[test]
+    fn seal_open_roundtrip_chacha20_poly1305() {
+        let (sk_r, pk_r) = test_keypair();
+
+        let suite = Suite { aead: Algorithm::ChaCha20_Poly1305 };
+        let info = b"quiche ech test";
+        let aad = b"";
+        let pt = b"another secret";
+
+        let (enc, ct) = seal(suite, &pk_r, info, aad, pt).unwrap();
+        let recovered = open(suite, &sk_r, &enc, info, aad, &ct).unwrap();
+
+        assert_eq!(&recovered, pt);
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_to_open() {
+        let (sk_r, pk_r) = test_keypair();
+
+        let suite = Suite { aead: Algorithm::AES128_GCM };
+        let info = b"quiche ech test";
+        let aad = b"associated data";
+
+        let (enc, mut ct) = seal(suite, &pk_r, info, aad, b"hello").unwrap();
+        let last = ct.len() - 1;
+        ct[last] ^= 0xff;
+
+        assert!(open(suite, &sk_r, &enc, info, aad, &ct).is_err());
+    }
+
+    #[test]
+    fn unsupported_aead_is_rejected_not_panicking() {
+        let suite = Suite { aead: Algorithm::AES256_GCM };
+
+        assert!(suite.aead_id().is_err());
+    }
+}